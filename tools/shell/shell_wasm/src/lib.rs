@@ -1,12 +1,49 @@
 #![no_std]
 #![no_main]
 
+use core::fmt::Write;
 use core::panic::PanicInfo;
 
+/// Fixed-capacity `core::fmt::Write` sink over a stack buffer, since there is
+/// no allocator to back a `String`. Writes past capacity are silently dropped.
+struct PanicBuf<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Write for PanicBuf<'a> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let bytes = s.as_bytes();
+        let remaining = self.buf.len() - self.len;
+        let n = if bytes.len() < remaining { bytes.len() } else { remaining };
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Format the panic location and message into a stack buffer, print it locally,
+/// and ship it to the node log/console actor so crashes are captured off-actor
+/// even when the actor itself is about to halt. Mirrors ARTIQ's `panic_fmt`.
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    let msg = b"panic in shell wasm\n";
-    unsafe { mk_print(msg.as_ptr(), msg.len() as i32) };
+fn panic(info: &PanicInfo) -> ! {
+    let mut storage = [0u8; 256];
+    let mut out = PanicBuf { buf: &mut storage, len: 0 };
+    let _ = match info.location() {
+        Some(loc) => write!(out, "panic at {}:{}:{}: {}", loc.file(), loc.line(), loc.column(), info.message()),
+        None => write!(out, "panic: {}", info.message()),
+    };
+    let len = out.len;
+
+    print_str("\n");
+    unsafe { mk_print(storage.as_ptr(), len as i32) };
+    print_str("\n");
+
+    let log = find_log_actor();
+    if log != ACTOR_ID_INVALID {
+        unsafe { mk_send(log, MSG_LOG as i32, storage.as_ptr(), len as i32) };
+    }
+
     loop {}
 }
 
@@ -60,6 +97,7 @@ const MSG_MOUNT_REQUEST: u32 = 105;
 const MSG_MOUNT_RESPONSE: u32 = 106;
 const MSG_CAPS_REQUEST: u32 = 0xFF00001D;
 const MSG_CAPS_REPLY: u32 = 0xFF00001E;
+const MSG_LOG: u32 = 107;
 
 // Cloudflare proxy message types
 const MSG_CF_KV_PUT: u32 = 300;
@@ -76,6 +114,112 @@ const MSG_CF_NOT_FOUND: u32 = 315;
 #[allow(dead_code)]
 const MSG_CF_ERROR: u32 = 316;
 
+/// Safe, typed wrappers over the raw `mk_*` host imports.
+///
+/// Every caller used to repeat `unsafe { mk_* }` and then branch on magic
+/// `i32` return codes (`0`, `-1`, `-2` for timeout) with no shared meaning.
+/// These wrappers give a single, exhaustive error type instead.
+mod safe {
+    use super::*;
+
+    /// Typed outcome of a failed `mk_*` call, replacing raw sentinel codes.
+    pub enum MkError {
+        Timeout,
+        #[allow(dead_code)]
+        NotFound,
+        #[allow(dead_code)]
+        BufferTooSmall,
+        SendFailed,
+        Io(i32),
+    }
+
+    pub struct Recv {
+        pub ty: u32,
+        pub size: u32,
+        pub source: i64,
+    }
+
+    pub fn send(dest: i64, ty: i32, payload: &[u8]) -> Result<(), MkError> {
+        let rc = unsafe {
+            if payload.is_empty() {
+                mk_send(dest, ty, core::ptr::null(), 0)
+            } else {
+                mk_send(dest, ty, payload.as_ptr(), payload.len() as i32)
+            }
+        };
+        if rc != 0 {
+            Ok(())
+        } else {
+            Err(MkError::SendFailed)
+        }
+    }
+
+    pub fn recv_timeout(buf: &mut [u8], timeout_ms: i32) -> Result<Recv, MkError> {
+        let mut ty: u32 = 0;
+        let mut size: u32 = 0;
+        let mut source: i64 = 0;
+        let rc = unsafe {
+            mk_recv_timeout(
+                &mut ty, buf.as_mut_ptr(), buf.len() as i32,
+                &mut size, &mut source, timeout_ms,
+            )
+        };
+        if rc == -2 {
+            Err(MkError::Timeout)
+        } else if rc < 0 {
+            Err(MkError::Io(rc))
+        } else {
+            Ok(Recv { ty, size, source })
+        }
+    }
+
+    pub fn lookup(name: &[u8]) -> Option<i64> {
+        let id = unsafe { mk_lookup(name.as_ptr(), name.len() as i32) };
+        if id == ACTOR_ID_INVALID {
+            None
+        } else {
+            Some(id)
+        }
+    }
+
+    pub fn register(name: &[u8]) -> Result<(), MkError> {
+        let rc = unsafe { mk_register(name.as_ptr(), name.len() as i32) };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(MkError::Io(rc))
+        }
+    }
+
+    pub fn stop(id: i64) -> Result<(), MkError> {
+        let rc = unsafe { mk_stop(id) };
+        if rc == 0 {
+            Ok(())
+        } else {
+            Err(MkError::Io(rc))
+        }
+    }
+}
+
+/// Print a consistent `error: ...` message for a typed `MkError`, and mark
+/// the current dispatched command as failed for `cmd_run`'s benefit.
+fn print_err(e: safe::MkError) {
+    unsafe { LAST_CMD_OK = false; }
+    print_str("error: ");
+    match e {
+        safe::MkError::Timeout => print_str("timeout"),
+        safe::MkError::NotFound => print_str("not found"),
+        safe::MkError::BufferTooSmall => print_str("buffer too small"),
+        safe::MkError::SendFailed => print_str("send failed"),
+        safe::MkError::Io(code) => {
+            print_str("io error (");
+            print_i32(code);
+            print_str(")");
+        }
+    }
+    print_str("\n");
+}
+
 // Buffer sizes
 const INPUT_BUF_SIZE: usize = 1024;
 const FILE_BUF_SIZE: usize = 32768; // 32KB — keeps WASM to 1 page (64KB)
@@ -84,8 +228,255 @@ const FILE_BUF_SIZE: usize = 32768; // 32KB — keeps WASM to 1 page (64KB)
 // Safety: single-threaded WASM execution, no concurrent access.
 static mut INPUT_BUF: [u8; INPUT_BUF_SIZE] = [0u8; INPUT_BUF_SIZE];
 static mut FILE_BUF: [u8; FILE_BUF_SIZE] = [0u8; FILE_BUF_SIZE];
+// Holds a script's text across `source`/`run` dispatch, since the commands
+// it dispatches (load, caps, ...) reuse FILE_BUF as their own scratch space.
+static mut SCRIPT_BUF: [u8; FILE_BUF_SIZE] = [0u8; FILE_BUF_SIZE];
+
+const MAX_SOURCE_DEPTH: u32 = 8;
+static mut SOURCE_DEPTH: u32 = 0;
+
+// Set to `false` by `dispatch_command` whenever it hits an unknown command
+// or a malformed `history run`, and by `print_err` for a typed `MkError`.
+// Other commands' own usage and `error: ...` messages leave it `true`.
+// Read back by `cmd_run` to stop a batch script early on the first failing line.
+static mut LAST_CMD_OK: bool = true;
+
+/// Output rendering mode for commands that produce tabular/structured data.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+    Csv,
+}
+
+// Safety: single-threaded WASM execution, no concurrent access.
+static mut OUTPUT_FORMAT: OutputFormat = OutputFormat::Text;
+
+/// Where `print`/`print_str` send their bytes. `dispatch_command` switches
+/// to `Buffer` for the duration of a `cmd > key`-redirected command, then
+/// flushes `OUTPUT_CAPTURE` to KV and switches back.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputSink {
+    Console,
+    Buffer,
+}
+
+const OUTPUT_CAPTURE_SIZE: usize = 4096;
+
+// Safety: single-threaded WASM execution, no concurrent access.
+static mut OUTPUT_SINK: OutputSink = OutputSink::Console;
+static mut OUTPUT_CAPTURE: [u8; OUTPUT_CAPTURE_SIZE] = [0u8; OUTPUT_CAPTURE_SIZE];
+static mut OUTPUT_CAPTURE_LEN: usize = 0;
+
+// --- Pending-message ring buffer -------------------------------------------
+//
+// `mk_call` blocks the caller in a recv loop until it sees one of the
+// reply types it's waiting for. Anything else that arrives in the
+// meantime (a `MSG_SHELL_INPUT` line the user typed mid-round-trip, or an
+// unrelated unsolicited message) would otherwise be silently swallowed by
+// that inner loop. Instead it's copied into this small fixed-capacity
+// ring for the outer REPL to drain before its own next `mk_recv_full`.
+
+const PENDING_CAP: usize = 4;
+const PENDING_PAYLOAD_MAX: usize = 256;
+
+#[derive(Clone, Copy)]
+struct PendingMsg {
+    ty: u32,
+    source: i64,
+    size: u32,
+    data: [u8; PENDING_PAYLOAD_MAX],
+}
+
+// Safety: single-threaded WASM execution, no concurrent access.
+static mut PENDING: [Option<PendingMsg>; PENDING_CAP] = [None; PENDING_CAP];
+static mut PENDING_COUNT: usize = 0;
+
+/// Buffer a message the REPL hasn't had a chance to see yet. Drops the
+/// message (rather than growing unbounded) if the ring is full.
+fn pending_push(ty: u32, source: i64, buf: &[u8], size: u32) {
+    unsafe {
+        if PENDING_COUNT >= PENDING_CAP {
+            return;
+        }
+        let mut data = [0u8; PENDING_PAYLOAD_MAX];
+        let n = (size as usize).min(buf.len()).min(PENDING_PAYLOAD_MAX);
+        data[..n].copy_from_slice(&buf[..n]);
+        PENDING[PENDING_COUNT] = Some(PendingMsg { ty, source, size: n as u32, data });
+        PENDING_COUNT += 1;
+    }
+}
+
+/// Pop the oldest buffered message, if any, preserving arrival order.
+fn pending_pop_front() -> Option<PendingMsg> {
+    unsafe {
+        if PENDING_COUNT == 0 {
+            return None;
+        }
+        let front = PENDING[0].take();
+        for i in 1..PENDING_COUNT {
+            PENDING[i - 1] = PENDING[i].take();
+        }
+        PENDING_COUNT -= 1;
+        front
+    }
+}
+
+fn is_cf_reply(ty: u32) -> bool {
+    matches!(ty, MSG_CF_OK | MSG_CF_VALUE | MSG_CF_KEYS | MSG_CF_NOT_FOUND | MSG_CF_ERROR)
+}
+
+/// Synchronous request/reply round trip with correlation: sends `payload`
+/// as `req_type` to `dest`, then loops on `mk_recv_timeout` until a reply
+/// whose type is in `expected_types` arrives (an empty slice matches any
+/// type except `MSG_SHELL_INPUT`, which is always buffered). Anything else received along the way is routed out of band
+/// instead of being dropped: `MSG_SHELL_INPUT` and other unsolicited
+/// messages are buffered in `PENDING` for the REPL to drain, while stray
+/// CF acknowledgements that don't match are discarded. On success the
+/// reply payload is left in `INPUT_BUF`; its type, size, and genuine
+/// sender are returned.
+fn mk_call(
+    dest: i64, req_type: i32, payload: &[u8],
+    expected_types: &[u32], timeout_ms: i32,
+) -> Result<(u32, usize, i64), safe::MkError> {
+    safe::send(dest, req_type, payload)?;
+
+    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
+    loop {
+        let r = safe::recv_timeout(input_buf, timeout_ms)?;
+
+        if r.ty == MSG_SHELL_INPUT {
+            pending_push(r.ty, r.source, input_buf, r.size);
+            continue;
+        }
+
+        if expected_types.is_empty() || expected_types.contains(&r.ty) {
+            return Ok((r.ty, r.size as usize, r.source));
+        }
+
+        if is_cf_reply(r.ty) {
+            continue;
+        }
+
+        pending_push(r.ty, r.source, input_buf, r.size);
+    }
+}
+
+// --- Pipelined RPC queue ----------------------------------------------------
+//
+// `mk_call` is one strictly sequential round trip. Fanning a request out to
+// N keys with it costs N round trips end to end. `rpc_enqueue` fires a
+// request without waiting, and `rpc_drain` later matches each reply back to
+// its descriptor and reports it through a caller-supplied handler — turning
+// the N round trips into one pipelined burst.
+
+const RPC_QUEUE_CAP: usize = 8;
+
+#[derive(Clone, Copy)]
+struct RpcDescriptor {
+    tag: u32,
+    dest: i64,
+    expected_type: u32,
+}
+
+// Safety: single-threaded WASM execution, no concurrent access.
+static mut RPC_QUEUE: [Option<RpcDescriptor>; RPC_QUEUE_CAP] = [None; RPC_QUEUE_CAP];
+static mut RPC_HEAD: usize = 0;
+static mut RPC_COUNT: usize = 0;
+static mut RPC_NEXT_TAG: u32 = 1;
+
+/// Slots free in the outstanding-request ring.
+fn rpc_capacity_remaining() -> usize {
+    RPC_QUEUE_CAP - unsafe { RPC_COUNT }
+}
+
+/// Two reply types are considered the same round-trip slot if they're
+/// identical, or if both are CF proxy replies (a GET may come back as
+/// either `MSG_CF_VALUE` or `MSG_CF_NOT_FOUND`, and either completes the
+/// descriptor that was waiting on it).
+fn rpc_type_matches(expected: u32, actual: u32) -> bool {
+    expected == actual || (is_cf_reply(expected) && is_cf_reply(actual))
+}
+
+/// Fire `payload` as `req_type` to `dest` without waiting for the reply,
+/// registering `expected_type` so `rpc_drain` can recognize its answer.
+/// Returns the request's correlation tag, or an error if the ring is full
+/// or the send itself fails.
+fn rpc_enqueue(dest: i64, req_type: i32, expected_type: u32, payload: &[u8]) -> Result<u32, safe::MkError> {
+    if rpc_capacity_remaining() == 0 {
+        return Err(safe::MkError::BufferTooSmall);
+    }
+    safe::send(dest, req_type, payload)?;
+
+    let tag = unsafe {
+        let t = RPC_NEXT_TAG;
+        RPC_NEXT_TAG = if t == u32::MAX { 1 } else { t + 1 };
+        t
+    };
+    unsafe {
+        let idx = (RPC_HEAD + RPC_COUNT) % RPC_QUEUE_CAP;
+        RPC_QUEUE[idx] = Some(RpcDescriptor { tag, dest, expected_type });
+        RPC_COUNT += 1;
+    }
+    Ok(tag)
+}
+
+/// Drain outstanding requests, calling `handler(tag, dest, reply_type,
+/// reply_size)` once per completed reply (the reply payload is left in
+/// `INPUT_BUF` for the duration of that call). CF replies preserve request
+/// ordering per actor, so replies are matched against the oldest
+/// outstanding descriptor first — but only if the reply's `source` is
+/// actually that descriptor's `dest`, so a same-type reply from an
+/// unrelated actor can't be attributed to it. Shell input and unrelated
+/// unsolicited messages are buffered via `pending_push` rather than
+/// dropped. Stops once the ring is empty or a receive fails/times out.
+fn rpc_drain<F: FnMut(u32, i64, u32, usize)>(timeout_ms: i32, mut handler: F) {
+    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
+
+    while unsafe { RPC_COUNT } > 0 {
+        let r = match safe::recv_timeout(input_buf, timeout_ms) {
+            Ok(r) => r,
+            Err(_) => break,
+        };
+
+        if r.ty == MSG_SHELL_INPUT {
+            pending_push(r.ty, r.source, input_buf, r.size);
+            continue;
+        }
+
+        let matched = unsafe {
+            let head = RPC_HEAD;
+            let is_match = matches!(
+                &RPC_QUEUE[head],
+                Some(d) if d.dest == r.source && rpc_type_matches(d.expected_type, r.ty)
+            );
+            if is_match {
+                let d = RPC_QUEUE[head].take().unwrap();
+                RPC_HEAD = (RPC_HEAD + 1) % RPC_QUEUE_CAP;
+                RPC_COUNT -= 1;
+                Some(d)
+            } else {
+                None
+            }
+        };
+
+        match matched {
+            Some(d) => handler(d.tag, d.dest, r.ty, r.size as usize),
+            None if is_cf_reply(r.ty) => {}
+            None => pending_push(r.ty, r.source, input_buf, r.size),
+        }
+    }
+}
 
 fn print(s: &[u8]) {
+    if unsafe { OUTPUT_SINK } == OutputSink::Buffer {
+        let cap = unsafe { &mut *core::ptr::addr_of_mut!(OUTPUT_CAPTURE) };
+        let len = unsafe { OUTPUT_CAPTURE_LEN };
+        let n = s.len().min(cap.len() - len);
+        cap[len..len + n].copy_from_slice(&s[..n]);
+        unsafe { OUTPUT_CAPTURE_LEN = len + n; }
+        return;
+    }
     unsafe { mk_print(s.as_ptr(), s.len() as i32) };
 }
 
@@ -117,6 +508,97 @@ fn print_i32(n: i32) {
     }
 }
 
+/// Write the decimal digits of `n` into `buf` at `off`, returning the offset just past them.
+fn write_u64_into(buf: &mut [u8], off: usize, mut n: u64) -> usize {
+    if n == 0 {
+        buf[off] = b'0';
+        return off + 1;
+    }
+    let mut tmp = [0u8; 20];
+    let mut i = 20;
+    while n > 0 {
+        i -= 1;
+        tmp[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+    }
+    let len = 20 - i;
+    buf[off..off + len].copy_from_slice(&tmp[i..20]);
+    off + len
+}
+
+/// Escape `s` as a JSON string body (no surrounding quotes) into `buf` at `off`.
+/// Stops (truncating `s`) rather than overrunning `buf` if it fills up, since
+/// a single control byte can expand up to 6x (backslash-u-escaped).
+fn json_escape_into(buf: &mut [u8], off: usize, s: &[u8]) -> usize {
+    let mut off = off;
+    for &b in s {
+        let needed = match b {
+            b'"' | b'\\' => 2,
+            0x00..=0x1f => 6,
+            _ => 1,
+        };
+        if off + needed > buf.len() {
+            break;
+        }
+        match b {
+            b'"' => {
+                buf[off] = b'\\';
+                buf[off + 1] = b'"';
+                off += 2;
+            }
+            b'\\' => {
+                buf[off] = b'\\';
+                buf[off + 1] = b'\\';
+                off += 2;
+            }
+            0x00..=0x1f => {
+                let hex = b"0123456789abcdef";
+                buf[off] = b'\\';
+                buf[off + 1] = b'u';
+                buf[off + 2] = b'0';
+                buf[off + 3] = b'0';
+                buf[off + 4] = hex[(b >> 4) as usize];
+                buf[off + 5] = hex[(b & 0xf) as usize];
+                off += 6;
+            }
+            _ => {
+                buf[off] = b;
+                off += 1;
+            }
+        }
+    }
+    off
+}
+
+/// Escape `s` as a quoted CSV field (with surrounding quotes) into `buf` at
+/// `off`, doubling embedded `"` per RFC 4180. Stops (truncating `s`) rather
+/// than overrunning `buf` if it fills up.
+fn csv_escape_into(buf: &mut [u8], off: usize, s: &[u8]) -> usize {
+    let mut off = off;
+    if off >= buf.len() {
+        return off;
+    }
+    buf[off] = b'"';
+    off += 1;
+    for &b in s {
+        let needed = if b == b'"' { 2 } else { 1 };
+        if off + needed > buf.len() {
+            break;
+        }
+        if b == b'"' {
+            buf[off] = b'"';
+            off += 1;
+        }
+        buf[off] = b;
+        off += 1;
+    }
+    if off < buf.len() {
+        buf[off] = b'"';
+        off += 1;
+    }
+    off
+}
+
 fn trim(s: &[u8]) -> &[u8] {
     let mut start = 0;
     let mut end = s.len();
@@ -160,10 +642,6 @@ fn parse_u16(s: &[u8]) -> u16 {
     n
 }
 
-fn lookup_name(name: &[u8]) -> i64 {
-    unsafe { mk_lookup(name.as_ptr(), name.len() as i32) }
-}
-
 fn split_first_space(s: &[u8]) -> (&[u8], &[u8]) {
     for i in 0..s.len() {
         if s[i] == b' ' || s[i] == b'\t' {
@@ -174,6 +652,25 @@ fn split_first_space(s: &[u8]) -> (&[u8], &[u8]) {
     (s, b"")
 }
 
+/// Split a trailing ` > key` output redirection off a command line, e.g.
+/// `list > snapshot`. Scans for the last ` > ` so a key itself can't contain
+/// one. Returns the command with the redirection stripped, plus the key if
+/// one was found.
+fn split_redirect(line: &[u8]) -> (&[u8], Option<&[u8]>) {
+    let mut i = line.len();
+    while i >= 3 {
+        i -= 1;
+        if line[i - 2] == b' ' && line[i - 1] == b'>' && line[i] == b' ' {
+            let key = trim(&line[i + 1..]);
+            if !key.is_empty() {
+                return (trim(&line[..i - 2]), Some(key));
+            }
+            break;
+        }
+    }
+    (line, None)
+}
+
 /// Resolve a target argument: try name lookup first, fall back to numeric ID.
 fn resolve_target(arg: &[u8]) -> Option<i64> {
     let id = unsafe { mk_lookup(arg.as_ptr(), arg.len() as i32) };
@@ -226,6 +723,81 @@ fn print_msg_payload(buf: &[u8], size: u32) {
     }
 }
 
+/// How to interpret the payload of a known reply message type.
+enum DecodeKind {
+    /// An 8-byte little/native-endian id followed by a named string field.
+    I64Then(&'static str),
+    /// Newline-separated entries — `key=value` pairs or a plain list of
+    /// values, one per line.
+    LineList,
+}
+
+/// Message types this shell knows how to render as labeled fields instead
+/// of the generic quoted-string dump (`print_msg_payload`).
+const DECODERS: &[(u32, DecodeKind)] = &[
+    (MSG_SPAWN_RESPONSE, DecodeKind::I64Then("name")),
+    (MSG_CF_VALUE, DecodeKind::LineList),
+    // CF_KV_LIST replies are newline-separated keys, not len-prefixed
+    // records — see cmd_history's own parsing of the same reply type.
+    (MSG_CF_KEYS, DecodeKind::LineList),
+];
+
+fn find_decoder(msg_type: u32) -> Option<&'static DecodeKind> {
+    for (ty, kind) in DECODERS {
+        if *ty == msg_type {
+            return Some(kind);
+        }
+    }
+    None
+}
+
+fn decode_i64_then(buf: &[u8], size: u32, field_name: &str) {
+    if size < 8 {
+        print_str(" (truncated)");
+        return;
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    let id = i64::from_ne_bytes(bytes);
+    print_str(" id=");
+    print_u64(id as u64);
+    if size > 8 {
+        let len = core::cmp::min(size as usize, buf.len());
+        print_str(" ");
+        print_str(field_name);
+        print_str("=\"");
+        print(&buf[8..len]);
+        print_str("\"");
+    }
+}
+
+fn decode_line_list(buf: &[u8], size: u32) {
+    let len = core::cmp::min(size as usize, buf.len());
+    print_str("\n");
+    let mut pos = 0;
+    while pos < len {
+        let mut end = pos;
+        while end < len && buf[end] != b'\n' { end += 1; }
+        if end > pos {
+            print_str("  ");
+            print(&buf[pos..end]);
+            print_str("\n");
+        }
+        pos = end + 1;
+    }
+}
+
+/// Render a reply payload using the registered decoder for `msg_type`, or
+/// fall back to the generic quoted-string dump (`print_msg_payload`) if none
+/// is registered.
+fn print_msg_payload_decoded(msg_type: u32, buf: &[u8], size: u32) {
+    match find_decoder(msg_type) {
+        Some(DecodeKind::I64Then(field_name)) => decode_i64_then(buf, size, field_name),
+        Some(DecodeKind::LineList) => decode_line_list(buf, size),
+        None => print_msg_payload(buf, size),
+    }
+}
+
 fn cmd_whoami() {
     let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
     let len = unsafe { mk_node_name(input_buf.as_mut_ptr(), input_buf.len() as i32) };
@@ -260,7 +832,104 @@ fn cmd_ns_list(prefix: &[u8]) {
         return;
     }
     let len = core::cmp::min(size_out as usize, file_buf.len());
-    print(&file_buf[..len]);
+
+    let fmt = unsafe { OUTPUT_FORMAT };
+    if fmt == OutputFormat::Text {
+        print(&file_buf[..len]);
+        return;
+    }
+
+    // Stash the raw newline-separated listing in SCRIPT_BUF (sized like
+    // FILE_BUF) so FILE_BUF is free to be rebuilt in place as JSON/CSV
+    // without truncating listings bigger than the much smaller INPUT_BUF.
+    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(SCRIPT_BUF) };
+    let copy_len = len.min(input_buf.len());
+    input_buf[..copy_len].copy_from_slice(&file_buf[..copy_len]);
+
+    let mut off = 0usize;
+    let mut truncated = false;
+    if fmt == OutputFormat::Json {
+        file_buf[off] = b'[';
+        off += 1;
+        let mut first = true;
+        let mut pos = 0;
+        while pos < copy_len {
+            let mut end = pos;
+            while end < copy_len && input_buf[end] != b'\n' { end += 1; }
+            if end > pos {
+                let entry = &input_buf[pos..end];
+                // Reserve room for this record's worst case — a leading
+                // comma, the surrounding quotes, and a fully-escaped entry
+                // (each byte can expand up to 6x) — plus the closing `]\n`.
+                // Bail with a marker instead of overrunning FILE_BUF.
+                let margin = 1 + 2 + entry.len() * 6 + 2;
+                if off + margin > file_buf.len() {
+                    truncated = true;
+                    break;
+                }
+                if !first {
+                    file_buf[off] = b',';
+                    off += 1;
+                }
+                first = false;
+                file_buf[off] = b'"';
+                off += 1;
+                off = json_escape_into(file_buf, off, entry);
+                file_buf[off] = b'"';
+                off += 1;
+            }
+            pos = end + 1;
+        }
+        file_buf[off] = b']';
+        off += 1;
+        file_buf[off] = b'\n';
+        off += 1;
+    } else {
+        let mut pos = 0;
+        while pos < copy_len {
+            let mut end = pos;
+            while end < copy_len && input_buf[end] != b'\n' { end += 1; }
+            if end > pos {
+                let entry = &input_buf[pos..end];
+                // Reserve room for a fully-quoted, `"`-doubled entry plus
+                // the trailing newline.
+                let margin = entry.len() * 2 + 2 + 1;
+                if off + margin > file_buf.len() {
+                    truncated = true;
+                    break;
+                }
+                off = csv_escape_into(file_buf, off, entry);
+                file_buf[off] = b'\n';
+                off += 1;
+            }
+            pos = end + 1;
+        }
+    }
+
+    print(&file_buf[..off]);
+    if truncated {
+        print_str("(truncated)\n");
+    }
+}
+
+fn cmd_format(arg: &[u8]) {
+    let fmt = match arg {
+        b"text" => OutputFormat::Text,
+        b"json" => OutputFormat::Json,
+        b"csv" => OutputFormat::Csv,
+        _ => {
+            print_str("usage: format <text|json|csv>\n");
+            return;
+        }
+    };
+    unsafe { OUTPUT_FORMAT = fmt; }
+    print_str("Output format: ");
+    print_str(match fmt {
+        OutputFormat::Text => "text",
+        OutputFormat::Json => "json",
+        OutputFormat::Csv => "csv",
+    });
+    print_str("\n");
 }
 
 fn cmd_help() {
@@ -269,6 +938,8 @@ fn cmd_help() {
     print_str("  list                              List active actors\n");
     print_str("  ls /prefix                        List namespace entries by prefix\n");
     print_str("  load <path-or-url>                Load WASM actor from file or URL\n");
+    print_str("  source <path-or-url>             Batch-execute commands from a script\n");
+    print_str("  run [--echo] <path-or-url>       Like source, but stops on first failure\n");
     print_str("  send <name-or-id> <type> [data]   Send message to actor\n");
     print_str("  call <name-or-id> <type> [data]   Send and wait for reply (5s)\n");
     print_str("  stop <name-or-id>                 Stop an actor\n");
@@ -276,7 +947,9 @@ fn cmd_help() {
     print_str("  lookup <name>                     Lookup actor by name\n");
     print_str("  mount <host>[:<port>]             Connect to remote node (default port 4200)\n");
     print_str("  caps [target]                    Query node capabilities\n");
-    print_str("  history [clear]                   Show or clear command history\n");
+    print_str("  history [clear|run <n>]           Show/clear history, or replay entry n\n");
+    print_str("  format <text|json|csv>           Set output format for list/ls/caps\n");
+    print_str("  <command> > <key>                Redirect a command's output into KV\n");
     print_str("  whoami                            Show node identity and actor ID\n");
     print_str("  self                              Print own actor ID\n");
     print_str("  exit                              Shut down\n");
@@ -290,81 +963,191 @@ fn cmd_list() {
         print_str("error: mk_list_actors failed\n");
         return;
     }
-    print_str("Active actors (");
-    print_u64(count as u64);
-    print_str("):\n");
+
     let mut name_buf = [0u8; 128];
-    for i in 0..count as usize {
-        print_str("  ");
-        print_u64(ids[i] as u64);
-        let len = unsafe {
-            mk_reverse_lookup(ids[i], name_buf.as_mut_ptr(), name_buf.len() as i32)
-        };
-        if len > 0 {
+    let fmt = unsafe { OUTPUT_FORMAT };
+
+    if fmt == OutputFormat::Text {
+        print_str("Active actors (");
+        print_u64(count as u64);
+        print_str("):\n");
+        for i in 0..count as usize {
             print_str("  ");
-            let n = core::cmp::min(len as usize, name_buf.len());
-            print(&name_buf[..n]);
-        } else {
-            print_str("  (unnamed)");
+            print_u64(ids[i] as u64);
+            let len = unsafe {
+                mk_reverse_lookup(ids[i], name_buf.as_mut_ptr(), name_buf.len() as i32)
+            };
+            if len > 0 {
+                print_str("  ");
+                let n = core::cmp::min(len as usize, name_buf.len());
+                print(&name_buf[..n]);
+            } else {
+                print_str("  (unnamed)");
+            }
+            print_str("\n");
         }
-        print_str("\n");
+        return;
     }
-}
 
-fn cmd_load(arg: &[u8]) {
-    if arg.is_empty() {
-        print_str("usage: load <path-or-url>\n");
-        return;
+    let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+    let mut off = 0usize;
+    let mut truncated = false;
+
+    if fmt == OutputFormat::Json {
+        file_buf[off] = b'[';
+        off += 1;
+        for i in 0..count as usize {
+            // Reserve room for this record's worst case — comma, fixed
+            // punctuation, full decimal id, and a fully-escaped name (each
+            // byte can expand up to 6x) — plus the closing `]\n`. Bail with
+            // a marker instead of overrunning FILE_BUF.
+            let margin = 1 + 6 + 20 + 9 + name_buf.len() * 6 + 2 + 2;
+            if off + margin > file_buf.len() {
+                truncated = true;
+                break;
+            }
+            if i > 0 {
+                file_buf[off] = b',';
+                off += 1;
+            }
+            let prefix = b"{\"id\":";
+            file_buf[off..off + prefix.len()].copy_from_slice(prefix);
+            off += prefix.len();
+            off = write_u64_into(file_buf, off, ids[i] as u64);
+            let mid = b",\"name\":\"";
+            file_buf[off..off + mid.len()].copy_from_slice(mid);
+            off += mid.len();
+            let len = unsafe {
+                mk_reverse_lookup(ids[i], name_buf.as_mut_ptr(), name_buf.len() as i32)
+            };
+            if len > 0 {
+                let n = core::cmp::min(len as usize, name_buf.len());
+                off = json_escape_into(file_buf, off, &name_buf[..n]);
+            }
+            file_buf[off] = b'"';
+            off += 1;
+            file_buf[off] = b'}';
+            off += 1;
+        }
+        file_buf[off] = b']';
+        off += 1;
+        file_buf[off] = b'\n';
+        off += 1;
+    } else {
+        let header = b"id,name\n";
+        file_buf[off..off + header.len()].copy_from_slice(header);
+        off += header.len();
+        for i in 0..count as usize {
+            // Reserve room for the id digits, a comma, a fully-quoted and
+            // `"`-doubled name, and the trailing newline.
+            let margin = 20 + 1 + name_buf.len() * 2 + 2 + 1;
+            if off + margin > file_buf.len() {
+                truncated = true;
+                break;
+            }
+            off = write_u64_into(file_buf, off, ids[i] as u64);
+            file_buf[off] = b',';
+            off += 1;
+            let len = unsafe {
+                mk_reverse_lookup(ids[i], name_buf.as_mut_ptr(), name_buf.len() as i32)
+            };
+            if len > 0 {
+                let n = core::cmp::min(len as usize, name_buf.len());
+                off = csv_escape_into(file_buf, off, &name_buf[..n]);
+            }
+            file_buf[off] = b'\n';
+            off += 1;
+        }
     }
 
-    let is_url = starts_with(arg, b"http://") || starts_with(arg, b"https://");
+    print(&file_buf[..off]);
+    if truncated {
+        print_str("(truncated)\n");
+    }
+}
 
-    let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+/// Where a `fetch_into` load came from, so callers can phrase their own
+/// status message ("Downloaded" vs "Read ... from file").
+enum FetchSource {
+    File,
+    Http,
+}
+
+/// Load a local file or `http(s)://` URL into `buf`, the shared low-level
+/// path used by both `load` and `source`. Prints its own error message and
+/// returns `None` on failure; the byte count does not include any status
+/// line, callers report that themselves.
+fn fetch_into(arg: &[u8], buf: &mut [u8]) -> Option<(usize, FetchSource)> {
+    let is_url = starts_with(arg, b"http://") || starts_with(arg, b"https://");
     let mut size_out: u32 = 0;
 
-    if is_url {
+    let source = if is_url {
         let mut status: u32 = 0;
         let rc = unsafe {
             mk_http_get(
                 arg.as_ptr(), arg.len() as i32,
-                file_buf.as_mut_ptr(), file_buf.len() as i32,
+                buf.as_mut_ptr(), buf.len() as i32,
                 &mut status, &mut size_out,
             )
         };
         if rc < 0 {
             print_str("error: HTTP GET failed\n");
-            return;
+            return None;
         }
         if status != 200 {
             print_str("error: HTTP ");
             print_u64(status as u64);
             print_str("\n");
-            return;
+            return None;
         }
-        print_str("Downloaded ");
-        print_u64(size_out as u64);
-        print_str(" bytes\n");
+        FetchSource::Http
     } else {
         let rc = unsafe {
             mk_read_file(
                 arg.as_ptr(), arg.len() as i32,
-                file_buf.as_mut_ptr(), file_buf.len() as i32,
+                buf.as_mut_ptr(), buf.len() as i32,
                 &mut size_out,
             )
         };
         if rc < 0 {
             print_str("error: cannot read file\n");
-            return;
+            return None;
+        }
+        FetchSource::File
+    };
+
+    if size_out == 0 {
+        print_str("error: empty file/response\n");
+        return None;
+    }
+
+    Some((size_out as usize, source))
+}
+
+fn cmd_load(arg: &[u8]) {
+    if arg.is_empty() {
+        print_str("usage: load <path-or-url>\n");
+        return;
+    }
+
+    let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+    let (size_out, source) = match fetch_into(arg, file_buf) {
+        Some(v) => v,
+        None => return,
+    };
+    match source {
+        FetchSource::Http => {
+            print_str("Downloaded ");
+            print_u64(size_out as u64);
+            print_str(" bytes\n");
+        }
+        FetchSource::File => {
+            print_str("Read ");
+            print_u64(size_out as u64);
+            print_str(" bytes from file\n");
         }
-        print_str("Read ");
-        print_u64(size_out as u64);
-        print_str(" bytes from file\n");
-    }
-
-    if size_out == 0 {
-        print_str("error: empty file/response\n");
-        return;
     }
+    let size_out = size_out as u32;
 
     // Extract name from path/URL for auto-registration
     let name = extract_name(arg);
@@ -410,6 +1193,86 @@ fn cmd_load(arg: &[u8]) {
     print_str("Loading...\n");
 }
 
+/// Fetch a newline-separated command script from `path` into `SCRIPT_BUF`
+/// and feed each non-empty, non-comment (`#`) line to `dispatch`, guarded
+/// by the shared `source`/`run` include-loop depth counter. Shared by
+/// `cmd_source` (dispatches via `execute_line`, always continues) and
+/// `cmd_run` (dispatches via `dispatch_command`, optionally echoing each
+/// line and stopping at the first one that reports failure).
+fn run_script(path: &[u8], echo: bool, stop_on_failure: bool, dispatch: fn(&[u8]) -> bool) {
+    let depth = unsafe { SOURCE_DEPTH };
+    if depth >= MAX_SOURCE_DEPTH {
+        print_str("error: source depth exceeded (possible include loop)\n");
+        return;
+    }
+
+    let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+    let (size, _) = match fetch_into(path, file_buf) {
+        Some(v) => v,
+        None => return,
+    };
+
+    // Copy the script out of FILE_BUF before dispatching any of its lines,
+    // since commands like `load`/`caps` reuse FILE_BUF as scratch space.
+    let script_buf = unsafe { &mut *core::ptr::addr_of_mut!(SCRIPT_BUF) };
+    let len = size.min(script_buf.len());
+    script_buf[..len].copy_from_slice(&file_buf[..len]);
+
+    unsafe { SOURCE_DEPTH = depth + 1; }
+
+    let mut pos = 0;
+    while pos < len {
+        let mut end = pos;
+        while end < len && script_buf[end] != b'\n' { end += 1; }
+        let line = trim(&script_buf[pos..end]);
+        pos = end + 1;
+
+        if line.is_empty() || line[0] == b'#' {
+            continue;
+        }
+
+        if echo {
+            print_str("+ ");
+            print(line);
+            print_str("\n");
+        }
+
+        if !dispatch(line) && stop_on_failure {
+            print_str("error: run stopped (command failed)\n");
+            break;
+        }
+    }
+
+    unsafe { SOURCE_DEPTH = depth; }
+}
+
+/// Load a newline-separated command script and feed each non-empty,
+/// non-comment (`#`) line through the same dispatcher the interactive REPL
+/// uses. Guards against `source`-of-`source` include loops.
+fn cmd_source(arg: &[u8]) {
+    if arg.is_empty() {
+        print_str("usage: source <path-or-url>\n");
+        return;
+    }
+    run_script(arg, false, false, execute_line);
+}
+
+/// Load a newline-separated command script and feed each non-empty,
+/// non-comment (`#`) line straight through `dispatch_command`, stopping at
+/// the first line that reports failure. Accepts an optional leading
+/// `--echo` flag to print each line before it runs. Shares `source`'s
+/// depth guard against include loops.
+fn cmd_run(arg: &[u8]) {
+    let (first, rest) = split_first_space(arg);
+    let (echo, path) = if first == b"--echo" { (true, rest) } else { (false, arg) };
+
+    if path.is_empty() {
+        print_str("usage: run [--echo] <path-or-url>\n");
+        return;
+    }
+    run_script(path, echo, true, dispatch_command);
+}
+
 fn handle_spawn_response(payload: *const u8, size: u32) {
     if size < 8 || payload.is_null() {
         print_str("error: spawn failed (bad response)\n");
@@ -471,20 +1334,15 @@ fn cmd_send(arg: &[u8]) {
         }
     };
 
-    let rc = if payload.is_empty() {
-        unsafe { mk_send(dest, msg_type, core::ptr::null(), 0) }
-    } else {
-        unsafe { mk_send(dest, msg_type, payload.as_ptr(), payload.len() as i32) }
-    };
-
-    if rc != 0 {
-        print_str("Sent type=");
-        print_i32(msg_type);
-        print_str(" to actor ");
-        print_u64(dest as u64);
-        print_str("\n");
-    } else {
-        print_str("error: send failed\n");
+    match safe::send(dest, msg_type, payload) {
+        Ok(()) => {
+            print_str("Sent type=");
+            print_i32(msg_type);
+            print_str(" to actor ");
+            print_u64(dest as u64);
+            print_str("\n");
+        }
+        Err(e) => print_err(e),
     }
 }
 
@@ -517,50 +1375,23 @@ fn cmd_call(arg: &[u8]) {
         }
     };
 
-    // Send
-    let rc = if payload.is_empty() {
-        unsafe { mk_send(dest, msg_type, core::ptr::null(), 0) }
-    } else {
-        unsafe { mk_send(dest, msg_type, payload.as_ptr(), payload.len() as i32) }
-    };
-    if rc == 0 {
-        print_str("error: send failed\n");
-        return;
-    }
-
-    // Wait for reply with 5s timeout
-    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
-    let mut recv_type: u32 = 0;
-    let mut recv_size: u32 = 0;
-    let mut recv_source: i64 = 0;
-    let rc = unsafe {
-        mk_recv_timeout(
-            &mut recv_type,
-            input_buf.as_mut_ptr(),
-            input_buf.len() as i32,
-            &mut recv_size,
-            &mut recv_source,
-            5000,
-        )
-    };
-
-    if rc == -2 {
-        print_str("Timeout (5s)\n");
-        return;
-    }
-    if rc < 0 {
-        print_str("error: recv failed\n");
-        return;
+    // Send and wait for any reply, with a 5s timeout. Any shell input typed
+    // mid-round-trip is preserved (buffered for the REPL) rather than lost.
+    match mk_call(dest, msg_type, payload, &[], 5000) {
+        Ok((reply_type, size, reply_source)) => {
+            let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
+            print_str("[reply] type=");
+            print_u64(reply_type as u64);
+            print_str(" from=");
+            print_u64(reply_source as u64);
+            print_str(" size=");
+            print_u64(size as u64);
+            print_msg_payload_decoded(reply_type, input_buf, size as u32);
+            print_str("\n");
+        }
+        Err(safe::MkError::Timeout) => print_str("Timeout (5s)\n"),
+        Err(e) => print_err(e),
     }
-
-    print_str("[reply] type=");
-    print_u64(recv_type as u64);
-    print_str(" from=");
-    print_u64(recv_source as u64);
-    print_str(" size=");
-    print_u64(recv_size as u64);
-    print_msg_payload(input_buf, recv_size);
-    print_str("\n");
 }
 
 fn cmd_stop(arg: &[u8]) {
@@ -577,10 +1408,14 @@ fn cmd_stop(arg: &[u8]) {
             return;
         }
     };
-    unsafe { mk_stop(id) };
-    print_str("Stopped actor ");
-    print_u64(id as u64);
-    print_str("\n");
+    match safe::stop(id) {
+        Ok(()) => {
+            print_str("Stopped actor ");
+            print_u64(id as u64);
+            print_str("\n");
+        }
+        Err(e) => print_err(e),
+    }
 }
 
 fn cmd_register(arg: &[u8]) {
@@ -588,13 +1423,13 @@ fn cmd_register(arg: &[u8]) {
         print_str("usage: register <name>\n");
         return;
     }
-    let rc = unsafe { mk_register(arg.as_ptr(), arg.len() as i32) };
-    if rc == 0 {
-        print_str("Registered as '");
-        print(arg);
-        print_str("'\n");
-    } else {
-        print_str("error: register failed\n");
+    match safe::register(arg) {
+        Ok(()) => {
+            print_str("Registered as '");
+            print(arg);
+            print_str("'\n");
+        }
+        Err(e) => print_err(e),
     }
 }
 
@@ -603,13 +1438,13 @@ fn cmd_lookup(arg: &[u8]) {
         print_str("usage: lookup <name>\n");
         return;
     }
-    let id = unsafe { mk_lookup(arg.as_ptr(), arg.len() as i32) };
-    if id == ACTOR_ID_INVALID {
-        print_str("Not found\n");
-    } else {
-        print_str("Actor ");
-        print_u64(id as u64);
-        print_str("\n");
+    match safe::lookup(arg) {
+        Some(id) => {
+            print_str("Actor ");
+            print_u64(id as u64);
+            print_str("\n");
+        }
+        None => print_str("Not found\n"),
     }
 }
 
@@ -633,11 +1468,13 @@ fn cmd_mount(arg: &[u8]) {
     };
 
     // Look up console actor
-    let console_id = lookup_name(b"console");
-    if console_id == 0 {
-        print_str("error: console not found\n");
-        return;
-    }
+    let console_id = match safe::lookup(b"console") {
+        Some(v) => v,
+        None => {
+            print_str("error: console not found\n");
+            return;
+        }
+    };
 
     // Build payload: host_len(1) + host + port_le(2)
     let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
@@ -649,33 +1486,34 @@ fn cmd_mount(arg: &[u8]) {
     input_buf[2+hlen] = port_bytes[1];
     let total = 1 + hlen + 2;
 
-    unsafe { mk_send(console_id, MSG_MOUNT_REQUEST as i32,
-                      input_buf.as_ptr(), total as i32) };
+    if let Err(e) = safe::send(console_id, MSG_MOUNT_REQUEST as i32, &input_buf[..total]) {
+        print_err(e);
+        return;
+    }
 
     print_str("Connecting...\n");
 
     // Wait for response (5s timeout)
     let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
-    let mut src: i64 = 0;
-    let mut out_size: u32 = 0;
-    let mut msg_type: u32 = MSG_MOUNT_RESPONSE;
-    let rc = unsafe {
-        mk_recv_timeout(&mut msg_type, file_buf.as_mut_ptr(),
-                        file_buf.len() as i32, &mut out_size,
-                        &mut src, 5000)
+    let recv = match safe::recv_timeout(file_buf, 5000) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => {
+            print_str("Timeout\n");
+            return;
+        }
+        Err(e) => {
+            print_err(e);
+            return;
+        }
     };
 
-    if rc == -2 {
-        print_str("Timeout\n");
-        return;
-    }
-    if rc < 0 || out_size < 1 {
+    if recv.size < 1 {
         print_str("error: mount failed\n");
         return;
     }
 
-    if file_buf[0] == 0 && out_size > 1 {
-        let ident_len = (out_size as usize - 1).min(31);
+    if file_buf[0] == 0 && recv.size > 1 {
+        let ident_len = (recv.size as usize - 1).min(31);
         print_str("Mounted /node/");
         print(&file_buf[1..1+ident_len]);
         print_str("\n");
@@ -729,74 +1567,109 @@ fn cmd_caps(arg: &[u8]) {
     };
 
     // Send MSG_CAPS_REQUEST
-    let rc = unsafe { mk_send(target, MSG_CAPS_REQUEST as i32, core::ptr::null(), 0) };
-    if rc == 0 {
-        print_str("error: send failed\n");
+    if let Err(e) = safe::send(target, MSG_CAPS_REQUEST as i32, &[]) {
+        print_err(e);
         return;
     }
 
     // Wait for MSG_CAPS_REPLY with 5s timeout
-    let mut recv_type: u32 = 0;
-    let mut recv_size: u32 = 0;
-    let mut recv_source: i64 = 0;
-    let rc = unsafe {
-        mk_recv_timeout(
-            &mut recv_type,
-            input_buf.as_mut_ptr(),
-            input_buf.len() as i32,
-            &mut recv_size,
-            &mut recv_source,
-            5000,
-        )
+    let recv = match safe::recv_timeout(input_buf, 5000) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => {
+            print_str("Timeout (5s)\n");
+            return;
+        }
+        Err(e) => {
+            print_err(e);
+            return;
+        }
     };
 
-    if rc == -2 {
-        print_str("Timeout (5s)\n");
-        return;
-    }
-    if rc < 0 {
-        print_str("error: recv failed\n");
-        return;
-    }
-
-    if recv_type == MSG_CAPS_REPLY {
-        let len = core::cmp::min(recv_size as usize, input_buf.len());
-        print(&input_buf[..len]);
+    if recv.ty == MSG_CAPS_REPLY {
+        let len = core::cmp::min(recv.size as usize, input_buf.len());
+        render_caps_reply(&input_buf[..len]);
     } else {
         print_str("Unexpected reply type=");
-        print_u64(recv_type as u64);
+        print_u64(recv.ty as u64);
         print_str("\n");
     }
 }
 
+/// Render a raw `MSG_CAPS_REPLY` payload per the active `OUTPUT_FORMAT`.
+fn render_caps_reply(reply: &[u8]) {
+    match unsafe { OUTPUT_FORMAT } {
+        OutputFormat::Text => print(reply),
+        OutputFormat::Json => {
+            // Already JSON on the wire (object or array) — pass through as-is.
+            if !reply.is_empty() && (reply[0] == b'{' || reply[0] == b'[') {
+                print(reply);
+                return;
+            }
+            let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+            let mut off = 0usize;
+            let prefix = b"{\"caps\":\"";
+            file_buf[off..off + prefix.len()].copy_from_slice(prefix);
+            off += prefix.len();
+            off = json_escape_into(file_buf, off, reply);
+            file_buf[off] = b'"';
+            off += 1;
+            file_buf[off] = b'}';
+            off += 1;
+            file_buf[off] = b'\n';
+            off += 1;
+            print(&file_buf[..off]);
+        }
+        OutputFormat::Csv => {
+            let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+            let mut off = 0usize;
+            file_buf[off] = b'"';
+            off += 1;
+            for &b in reply {
+                if b == b'"' {
+                    file_buf[off] = b'"';
+                    off += 1;
+                }
+                file_buf[off] = b;
+                off += 1;
+            }
+            file_buf[off] = b'"';
+            off += 1;
+            file_buf[off] = b'\n';
+            off += 1;
+            print(&file_buf[..off]);
+        }
+    }
+}
+
 /// Look up the cf_proxy actor via /node/storage/kv path.
 fn find_kv_proxy() -> i64 {
     let path = b"/node/storage/kv";
     unsafe { mk_lookup(path.as_ptr(), path.len() as i32) }
 }
 
-/// Record a command to history via Cloudflare KV (fire-and-forget).
-fn history_record(line: &[u8]) {
+/// Look up the node log/console actor via /node/log, the same way `find_kv_proxy`
+/// resolves /node/storage/kv.
+fn find_log_actor() -> i64 {
+    let path = b"/node/log";
+    unsafe { mk_lookup(path.as_ptr(), path.len() as i32) }
+}
+
+/// Write `key=<key>\nvalue=<value>` to the Cloudflare KV proxy (fire-and-forget),
+/// building the payload into `FILE_BUF`. No-op if the proxy isn't mounted.
+fn kv_put(key: &[u8], value: &[u8]) {
     let kv = find_kv_proxy();
     if kv == ACTOR_ID_INVALID { return; }
 
-    let ts = unsafe { mk_time_ms() };
-
-    // Build payload: key=history/{timestamp_hex}\nvalue={command}
     let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
     let mut off = 0usize;
 
-    let prefix = b"key=history/";
+    let prefix = b"key=";
     file_buf[off..off + prefix.len()].copy_from_slice(prefix);
     off += prefix.len();
 
-    // Write timestamp as hex (16 chars, zero-padded)
-    let hex = b"0123456789abcdef";
-    for i in (0..16).rev() {
-        let nibble = ((ts as u64) >> (i * 4)) & 0xf;
-        file_buf[off] = hex[nibble as usize];
-        off += 1;
-    }
+    let key_len = key.len().min(FILE_BUF_SIZE - off);
+    file_buf[off..off + key_len].copy_from_slice(&key[..key_len]);
+    off += key_len;
 
     file_buf[off] = b'\n';
     off += 1;
@@ -804,13 +1677,30 @@ fn history_record(line: &[u8]) {
     file_buf[off..off + val_prefix.len()].copy_from_slice(val_prefix);
     off += val_prefix.len();
 
-    let cmd_len = line.len().min(FILE_BUF_SIZE - off);
-    file_buf[off..off + cmd_len].copy_from_slice(&line[..cmd_len]);
-    off += cmd_len;
+    let val_len = value.len().min(FILE_BUF_SIZE - off);
+    file_buf[off..off + val_len].copy_from_slice(&value[..val_len]);
+    off += val_len;
 
     unsafe { mk_send(kv, MSG_CF_KV_PUT as i32, file_buf.as_ptr(), off as i32) };
 }
 
+/// Record a command to history via Cloudflare KV (fire-and-forget).
+fn history_record(line: &[u8]) {
+    let ts = unsafe { mk_time_ms() };
+
+    // Build key: history/{timestamp_hex} (16 hex chars, zero-padded)
+    let mut key = [0u8; 24];
+    let prefix = b"history/";
+    key[..prefix.len()].copy_from_slice(prefix);
+    let hex = b"0123456789abcdef";
+    for i in (0..16).rev() {
+        let nibble = ((ts as u64) >> (i * 4)) & 0xf;
+        key[prefix.len() + (15 - i)] = hex[nibble as usize];
+    }
+
+    kv_put(&key, line);
+}
+
 fn cmd_history() {
     let kv = find_kv_proxy();
     if kv == ACTOR_ID_INVALID {
@@ -818,48 +1708,54 @@ fn cmd_history() {
         return;
     }
 
-    // Send KV LIST for history/ prefix
+    // List history/ keys
     let list_payload = b"prefix=history/\nlimit=20";
-    let rc = unsafe {
-        mk_send(kv, MSG_CF_KV_LIST as i32,
-                list_payload.as_ptr(), list_payload.len() as i32)
-    };
-    if rc == 0 {
-        print_str("error: send failed\n");
-        return;
-    }
-
-    // Wait for MSG_CF_KEYS reply
-    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
-    let mut recv_type: u32 = 0;
-    let mut recv_size: u32 = 0;
-    let mut recv_source: i64 = 0;
-    let rc = unsafe {
-        mk_recv_timeout(
-            &mut recv_type, input_buf.as_mut_ptr(), input_buf.len() as i32,
-            &mut recv_size, &mut recv_source, 5000,
-        )
+    let (recv_type, recv_size, _) = match mk_call(
+        kv, MSG_CF_KV_LIST as i32, list_payload,
+        &[MSG_CF_KEYS, MSG_CF_NOT_FOUND], 5000,
+    ) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => { print_str("Timeout\n"); return; }
+        Err(e) => { print_err(e); return; }
     };
-
-    if rc == -2 { print_str("Timeout\n"); return; }
-    if rc < 0 { print_str("error: recv failed\n"); return; }
-    if recv_type == MSG_CF_NOT_FOUND || recv_size == 0 {
-        print_str("(no history)\n");
-        return;
-    }
-    if recv_type != MSG_CF_KEYS {
+    if recv_type != MSG_CF_KEYS || recv_size == 0 {
         print_str("(no history)\n");
         return;
     }
 
     // Parse key list (newline-separated) and fetch each value
-    let keys_len = core::cmp::min(recv_size as usize, input_buf.len());
+    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
+    let keys_len = core::cmp::min(recv_size, input_buf.len());
 
     // Copy keys to FILE_BUF so we can reuse INPUT_BUF for GET requests
     let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
     let copy_len = keys_len.min(FILE_BUF_SIZE);
     file_buf[..copy_len].copy_from_slice(&input_buf[..copy_len]);
 
+    // Fire a GET for every key up front (pipelined, bounded by the RPC
+    // queue's capacity) instead of waiting on a reply before sending the
+    // next one, then drain the replies as they arrive.
+    let mut tag_index: [Option<(u32, u64)>; RPC_QUEUE_CAP] = [None; RPC_QUEUE_CAP];
+
+    fn report(tag: u32, ty: u32, size: usize, tag_index: &mut [Option<(u32, u64)>]) {
+        let slot = tag_index.iter_mut().find(|s| matches!(s, Some((t, _)) if *t == tag));
+        let index = match slot {
+            Some(s) => s.take().unwrap().1,
+            None => return,
+        };
+        if ty != MSG_CF_VALUE || size == 0 {
+            return;
+        }
+        let input_buf = unsafe { &*core::ptr::addr_of!(INPUT_BUF) };
+        let vlen = core::cmp::min(size, input_buf.len());
+        print_str("  ");
+        print_u64(index);
+        print_str(": ");
+        print(&input_buf[..vlen]);
+        print_str("\n");
+    }
+
+    let mut index: u64 = 0;
     let mut pos = 0;
     while pos < copy_len {
         // Find end of this key
@@ -870,6 +1766,12 @@ fn cmd_history() {
         if end == pos { pos = end + 1; continue; }
 
         let key = &file_buf[pos..end];
+        index += 1;
+
+        // Backpressure: drain outstanding replies before enqueuing more.
+        if rpc_capacity_remaining() == 0 {
+            rpc_drain(3000, |tag, _dest, ty, size| report(tag, ty, size, &mut tag_index));
+        }
 
         // Build GET payload: key={key}
         let prefix = b"key=";
@@ -878,67 +1780,131 @@ fn cmd_history() {
             input_buf[..prefix.len()].copy_from_slice(prefix);
             input_buf[prefix.len()..total].copy_from_slice(key);
 
-            let rc = unsafe {
-                mk_send(kv, MSG_CF_KV_GET as i32,
-                        input_buf.as_ptr(), total as i32)
-            };
-            if rc != 0 {
-                let mut gt: u32 = 0;
-                let mut gs: u32 = 0;
-                let mut gsrc: i64 = 0;
-                let rc = unsafe {
-                    mk_recv_timeout(
-                        &mut gt, input_buf.as_mut_ptr(), input_buf.len() as i32,
-                        &mut gs, &mut gsrc, 3000,
-                    )
-                };
-                if rc >= 0 && gt == MSG_CF_VALUE && gs > 0 {
-                    let vlen = core::cmp::min(gs as usize, input_buf.len());
-                    print_str("  ");
-                    print(&input_buf[..vlen]);
-                    print_str("\n");
+            if let Ok(tag) = rpc_enqueue(kv, MSG_CF_KV_GET as i32, MSG_CF_VALUE, &input_buf[..total]) {
+                if let Some(slot) = tag_index.iter_mut().find(|s| s.is_none()) {
+                    *slot = Some((tag, index));
                 }
             }
         }
 
         pos = end + 1;
     }
+
+    rpc_drain(3000, |tag, _dest, ty, size| report(tag, ty, size, &mut tag_index));
 }
 
-fn cmd_history_clear() {
+/// Re-execute history entry `n` (1-based, in listing order) by fetching its
+/// value from KV and routing it through the same dispatcher the REPL uses.
+fn cmd_history_run(n: u64) {
     let kv = find_kv_proxy();
     if kv == ACTOR_ID_INVALID {
         print_str("error: /node/storage/kv not available\n");
         return;
     }
 
-    // List all history keys
-    let list_payload = b"prefix=history/\nlimit=100";
-    let rc = unsafe {
-        mk_send(kv, MSG_CF_KV_LIST as i32,
-                list_payload.as_ptr(), list_payload.len() as i32)
+    let list_payload = b"prefix=history/\nlimit=20";
+    let (list_type, list_size, _) = match mk_call(
+        kv, MSG_CF_KV_LIST as i32, list_payload,
+        &[MSG_CF_KEYS, MSG_CF_NOT_FOUND], 5000,
+    ) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => { print_str("Timeout\n"); return; }
+        Err(e) => { print_err(e); return; }
     };
-    if rc == 0 { print_str("error: send failed\n"); return; }
+    if list_type != MSG_CF_KEYS || list_size == 0 {
+        print_str("(no history)\n");
+        return;
+    }
 
     let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
-    let mut recv_type: u32 = 0;
-    let mut recv_size: u32 = 0;
-    let mut recv_source: i64 = 0;
-    let rc = unsafe {
-        mk_recv_timeout(
-            &mut recv_type, input_buf.as_mut_ptr(), input_buf.len() as i32,
-            &mut recv_size, &mut recv_source, 5000,
-        )
+    let keys_len = core::cmp::min(list_size, input_buf.len());
+    let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
+    let copy_len = keys_len.min(FILE_BUF_SIZE);
+    file_buf[..copy_len].copy_from_slice(&input_buf[..copy_len]);
+
+    // Walk the newline-separated key list to the n-th (1-based) entry.
+    let mut index: u64 = 0;
+    let mut pos = 0;
+    let mut key_range: Option<(usize, usize)> = None;
+    while pos < copy_len {
+        let mut end = pos;
+        while end < copy_len && file_buf[end] != b'\n' { end += 1; }
+        if end > pos {
+            index += 1;
+            if index == n {
+                key_range = Some((pos, end));
+                break;
+            }
+        }
+        pos = end + 1;
+    }
+
+    let (kstart, kend) = match key_range {
+        Some(v) => v,
+        None => {
+            print_str("error: no such history entry\n");
+            return;
+        }
+    };
+
+    let prefix = b"key=";
+    let total = prefix.len() + (kend - kstart);
+    if total > input_buf.len() {
+        print_str("error: key too long\n");
+        return;
+    }
+    input_buf[..prefix.len()].copy_from_slice(prefix);
+    input_buf[prefix.len()..total].copy_from_slice(&file_buf[kstart..kend]);
+
+    let (value_type, value_size, _) = match mk_call(
+        kv, MSG_CF_KV_GET as i32, &input_buf[..total],
+        &[MSG_CF_VALUE, MSG_CF_NOT_FOUND], 3000,
+    ) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => { print_str("Timeout\n"); return; }
+        Err(e) => { print_err(e); return; }
     };
+    if value_type != MSG_CF_VALUE || value_size == 0 {
+        print_str("error: history entry not found\n");
+        return;
+    }
+
+    let vlen = core::cmp::min(value_size, input_buf.len());
+    let mut cmd_copy = [0u8; 256];
+    let copy_len = vlen.min(cmd_copy.len());
+    cmd_copy[..copy_len].copy_from_slice(&input_buf[..copy_len]);
+
+    print_str("> ");
+    print(&cmd_copy[..copy_len]);
+    print_str("\n");
+
+    execute_line(&cmd_copy[..copy_len]);
+}
 
-    if rc == -2 { print_str("Timeout\n"); return; }
-    if rc < 0 { print_str("error: recv failed\n"); return; }
+fn cmd_history_clear() {
+    let kv = find_kv_proxy();
+    if kv == ACTOR_ID_INVALID {
+        print_str("error: /node/storage/kv not available\n");
+        return;
+    }
+
+    // List all history keys
+    let list_payload = b"prefix=history/\nlimit=100";
+    let (recv_type, recv_size, _) = match mk_call(
+        kv, MSG_CF_KV_LIST as i32, list_payload,
+        &[MSG_CF_KEYS, MSG_CF_NOT_FOUND], 5000,
+    ) {
+        Ok(v) => v,
+        Err(safe::MkError::Timeout) => { print_str("Timeout\n"); return; }
+        Err(e) => { print_err(e); return; }
+    };
     if recv_type != MSG_CF_KEYS || recv_size == 0 {
         print_str("(no history to clear)\n");
         return;
     }
 
-    let keys_len = core::cmp::min(recv_size as usize, input_buf.len());
+    let input_buf = unsafe { &mut *core::ptr::addr_of_mut!(INPUT_BUF) };
+    let keys_len = core::cmp::min(recv_size, input_buf.len());
     let file_buf = unsafe { &mut *core::ptr::addr_of_mut!(FILE_BUF) };
     let copy_len = keys_len.min(FILE_BUF_SIZE);
     file_buf[..copy_len].copy_from_slice(&input_buf[..copy_len]);
@@ -970,12 +1936,82 @@ fn cmd_history_clear() {
     print_str(" history entries\n");
 }
 
-fn dispatch_command(line: &[u8]) {
+/// Dispatch one command line exactly as the interactive REPL would: trims
+/// it, checks for `exit`/`quit`, dispatches it, and records it to history
+/// (unless it's a `history` command itself). Shared by the REPL loop,
+/// `source`, and `history run`. Returns `true` if the shell should exit.
+fn execute_line(line: &[u8]) -> bool {
     let trimmed = trim(line);
     if trimmed.is_empty() {
-        return;
+        return false;
+    }
+
+    if trimmed == b"exit" || trimmed == b"quit" {
+        return true;
+    }
+
+    // Copy command before dispatch (commands like whoami overwrite INPUT_BUF)
+    let mut cmd_copy = [0u8; 256];
+    let cmd_len = trimmed.len().min(cmd_copy.len());
+    cmd_copy[..cmd_len].copy_from_slice(&trimmed[..cmd_len]);
+
+    dispatch_command(trimmed);
+
+    // Record non-empty commands to history (fire-and-forget)
+    if cmd_len > 0 && !starts_with(&cmd_copy[..cmd_len], b"history") {
+        history_record(&cmd_copy[..cmd_len]);
+    }
+
+    false
+}
+
+/// Parse and run one command line. Returns `true` unless the command was
+/// unknown, was a malformed `history run`, or reported a typed `MkError` via
+/// `print_err` — used by `cmd_run` to stop a batch script on the first
+/// failing line. Other commands' usage and `error: ...` messages still
+/// return `true`.
+fn dispatch_command(line: &[u8]) -> bool {
+    unsafe { LAST_CMD_OK = true; }
+
+    let trimmed = trim(line);
+    if trimmed.is_empty() {
+        return true;
+    }
+
+    let (trimmed, redirect_key) = split_redirect(trimmed);
+    if let Some(key) = redirect_key {
+        // Push: remember the outer sink/offset so a redirect nested inside
+        // a `source`/`run` script (itself dispatched through this same
+        // function) can't wipe or permanently divert the outer capture.
+        let prev_sink = unsafe { OUTPUT_SINK };
+        let start = unsafe { OUTPUT_CAPTURE_LEN };
+        unsafe { OUTPUT_SINK = OutputSink::Buffer; }
+
+        let ok = dispatch_command_inner(trimmed);
+
+        let captured = unsafe {
+            let cap = &*core::ptr::addr_of!(OUTPUT_CAPTURE);
+            &cap[start..OUTPUT_CAPTURE_LEN]
+        };
+        kv_put(key, captured);
+
+        // Pop: restore the outer sink and drop this level's bytes from the
+        // shared buffer rather than leaving them for the outer capture.
+        unsafe {
+            OUTPUT_SINK = prev_sink;
+            OUTPUT_CAPTURE_LEN = start;
+        }
+
+        print_str("(output saved to '");
+        print(key);
+        print_str("')\n");
+        return ok;
     }
 
+    dispatch_command_inner(trimmed)
+}
+
+fn dispatch_command_inner(trimmed: &[u8]) -> bool {
     let (cmd, arg) = split_first_space(trimmed);
 
     if cmd == b"help" || cmd == b"?" {
@@ -992,6 +2028,8 @@ fn dispatch_command(line: &[u8]) {
         cmd_whoami();
     } else if cmd == b"load" {
         cmd_load(arg);
+    } else if cmd == b"source" {
+        cmd_source(arg);
     } else if cmd == b"send" {
         cmd_send(arg);
     } else if cmd == b"call" {
@@ -1009,18 +2047,34 @@ fn dispatch_command(line: &[u8]) {
     } else if cmd == b"caps" {
         cmd_caps(arg);
     } else if cmd == b"history" {
-        if arg == b"clear" {
+        let (sub, rest) = split_first_space(arg);
+        if sub == b"clear" {
             cmd_history_clear();
+        } else if sub == b"run" {
+            match parse_u64(rest) {
+                Some(n) if n > 0 => cmd_history_run(n),
+                _ => {
+                    print_str("usage: history run <n>\n");
+                    unsafe { LAST_CMD_OK = false; }
+                }
+            }
         } else {
             cmd_history();
         }
+    } else if cmd == b"format" {
+        cmd_format(arg);
+    } else if cmd == b"run" {
+        cmd_run(arg);
     } else if cmd == b"exit" || cmd == b"quit" {
         // Handled by caller — won't reach here
     } else {
         print_str("Unknown command: ");
         print(cmd);
         print_str("\nType 'help' for available commands.\n");
+        unsafe { LAST_CMD_OK = false; }
     }
+
+    unsafe { LAST_CMD_OK }
 }
 
 #[no_mangle]
@@ -1048,22 +2102,31 @@ pub extern "C" fn handle_message(
             }
             need_prompt = true;
 
-            let mut recv_type: u32 = 0;
-            let mut recv_size: u32 = 0;
-            let mut recv_source: i64 = 0;
-            let rc = unsafe {
-                mk_recv_full(
-                    &mut recv_type,
-                    input_buf.as_mut_ptr(),
-                    input_buf.len() as i32,
-                    &mut recv_size,
-                    &mut recv_source,
-                )
+            // Drain anything mk_call buffered while it was mid-round-trip,
+            // before blocking on mk_recv_full for the next message.
+            let (recv_type, recv_size, recv_source) = if let Some(p) = pending_pop_front() {
+                let n = core::cmp::min(p.size as usize, input_buf.len());
+                input_buf[..n].copy_from_slice(&p.data[..n]);
+                (p.ty, p.size, p.source)
+            } else {
+                let mut recv_type: u32 = 0;
+                let mut recv_size: u32 = 0;
+                let mut recv_source: i64 = 0;
+                let rc = unsafe {
+                    mk_recv_full(
+                        &mut recv_type,
+                        input_buf.as_mut_ptr(),
+                        input_buf.len() as i32,
+                        &mut recv_size,
+                        &mut recv_source,
+                    )
+                };
+                if rc < 0 {
+                    print_str("error: mk_recv failed\n");
+                    break;
+                }
+                (recv_type, recv_size, recv_source)
             };
-            if rc < 0 {
-                print_str("error: mk_recv failed\n");
-                break;
-            }
 
             if recv_type == MSG_SPAWN_RESPONSE {
                 handle_spawn_response(input_buf.as_ptr(), recv_size);
@@ -1094,31 +2157,18 @@ pub extern "C" fn handle_message(
                 print_u64(recv_source as u64);
                 print_str(" size=");
                 print_u64(recv_size as u64);
-                print_msg_payload(input_buf, recv_size);
+                print_msg_payload_decoded(recv_type, input_buf, recv_size);
                 print_str("\n");
                 continue;
             }
 
             let len = core::cmp::min(recv_size as usize, input_buf.len());
             let line = &input_buf[..len];
-            let trimmed = trim(line);
 
-            if trimmed == b"exit" || trimmed == b"quit" {
+            if execute_line(line) {
                 print_str("Goodbye.\n");
                 return 0;
             }
-
-            // Copy command before dispatch (commands like whoami overwrite INPUT_BUF)
-            let mut cmd_copy = [0u8; 256];
-            let cmd_len = trimmed.len().min(cmd_copy.len());
-            cmd_copy[..cmd_len].copy_from_slice(&trimmed[..cmd_len]);
-
-            dispatch_command(trimmed);
-
-            // Record non-empty commands to history (fire-and-forget)
-            if cmd_len > 0 && !starts_with(&cmd_copy[..cmd_len], b"history") {
-                history_record(&cmd_copy[..cmd_len]);
-            }
         }
 
         return 0;